@@ -0,0 +1,563 @@
+//! Chat completion backends.
+//!
+//! Every AI command goes through [`AiProvider::complete`], which talks to
+//! either OpenAI or Anthropic depending on the [`ProviderConfig`] the bot was
+//! built with.
+
+use std::env;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use openai::chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole};
+use openai::set_key;
+use serde::{Deserialize, Serialize};
+
+const ANTHROPIC_MESSAGES_URL: &str = "https://api.anthropic.com/v1/messages";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+const DEFAULT_TIMEOUT_SECS: u64 = 45;
+const DEFAULT_MAX_TOKENS: u32 = 1024;
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+/// Which AI service answers commands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Provider {
+    OpenAi,
+    Anthropic,
+}
+
+impl Provider {
+    // Prefix of the environment variables configuring this provider.
+    fn env_prefix(self) -> &'static str {
+        match self {
+            Provider::OpenAi => "OPENAI",
+            Provider::Anthropic => "ANTHROPIC",
+        }
+    }
+
+    fn default_model(self) -> &'static str {
+        match self {
+            Provider::OpenAi => "gpt-3.5-turbo",
+            Provider::Anthropic => "claude-haiku-4-5",
+        }
+    }
+}
+
+/// How failed requests are retried.
+///
+/// Only transient failures (timeouts, rate limits, server errors and
+/// connection problems) are retried. The delay doubles after every attempt,
+/// with random jitter so concurrent requests don't retry in lockstep.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Total attempts per request, including the first. `1` disables retries.
+    pub max_attempts: u32,
+    /// Delay before the first retry.
+    pub base_delay: Duration,
+    /// Upper bound on the delay between attempts.
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    // Total attempts per request. Zero would mean never asking at all, so it
+    // is treated as one.
+    fn attempts(&self) -> u32 {
+        self.max_attempts.max(1)
+    }
+
+    // Delay before the attempt following `attempt` (1-based): somewhere
+    // between half and all of the exponential backoff.
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_delay);
+        let half = backoff / 2;
+        // Cheap jitter; it only needs to spread retries out, not be random.
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.subsec_nanos());
+        let jitter = half.mul_f64(f64::from(nanos) / 1_000_000_000.0);
+        half + jitter
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(8),
+        }
+    }
+}
+
+/// Provider selection plus the per-provider model, timeout, token limit and
+/// retry behaviour.
+#[derive(Clone, Debug)]
+pub struct ProviderConfig {
+    pub provider: Provider,
+    pub api_key: String,
+    pub model: String,
+    pub timeout: Duration,
+    pub max_tokens: u32,
+    pub retry: RetryPolicy,
+    /// A cheaper or more available model to use for the last attempt when
+    /// `model` keeps failing, e.g. `gpt-4o-mini` behind `gpt-4o`.
+    pub fallback_model: Option<String>,
+}
+
+impl ProviderConfig {
+    /// OpenAI with the default model and limits.
+    pub fn openai(api_key: impl Into<String>) -> Self {
+        Self::new(Provider::OpenAi, api_key.into())
+    }
+
+    /// Anthropic Claude with the default model and limits.
+    pub fn anthropic(api_key: impl Into<String>) -> Self {
+        Self::new(Provider::Anthropic, api_key.into())
+    }
+
+    fn new(provider: Provider, api_key: String) -> Self {
+        ProviderConfig {
+            provider,
+            api_key,
+            model: provider.default_model().to_string(),
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            max_tokens: DEFAULT_MAX_TOKENS,
+            retry: RetryPolicy::default(),
+            fallback_model: None,
+        }
+    }
+
+    /// Read the configuration from the environment.
+    ///
+    /// `AI_PROVIDER` picks the backend (`openai`, the default, or
+    /// `anthropic`). The selected provider then reads `<PREFIX>_API_KEY`
+    /// (required) and the optional `<PREFIX>_MODEL`, `<PREFIX>_TIMEOUT_SECS`,
+    /// `<PREFIX>_MAX_TOKENS`, `<PREFIX>_MAX_ATTEMPTS` and
+    /// `<PREFIX>_FALLBACK_MODEL`, where the prefix is `OPENAI` or `ANTHROPIC`.
+    pub fn from_env() -> Result<Self, String> {
+        let provider = match env::var("AI_PROVIDER") {
+            Ok(name) => match name.trim().to_lowercase().as_str() {
+                "" | "openai" => Provider::OpenAi,
+                "anthropic" | "claude" => Provider::Anthropic,
+                other => return Err(format!("unknown AI_PROVIDER '{}'", other)),
+            },
+            Err(_) => Provider::OpenAi,
+        };
+        let prefix = provider.env_prefix();
+
+        let key_var = format!("{}_API_KEY", prefix);
+        let api_key = env::var(&key_var).map_err(|_| format!("expected {} to be set", key_var))?;
+
+        let mut config = Self::new(provider, api_key);
+        if let Ok(model) = env::var(format!("{}_MODEL", prefix)) {
+            config.model = model;
+        }
+        if let Some(secs) = parse_var(&format!("{}_TIMEOUT_SECS", prefix))? {
+            config.timeout = Duration::from_secs(secs);
+        }
+        if let Some(max_tokens) = parse_var(&format!("{}_MAX_TOKENS", prefix))? {
+            config.max_tokens = max_tokens;
+        }
+        if let Some(max_attempts) = parse_var(&format!("{}_MAX_ATTEMPTS", prefix))? {
+            config.retry.max_attempts = max_attempts;
+        }
+        if let Ok(model) = env::var(format!("{}_FALLBACK_MODEL", prefix)) {
+            config.fallback_model = Some(model);
+        }
+        Ok(config)
+    }
+
+    // The model to ask on `attempt` (1-based) when `model` was requested:
+    // the fallback model, if any, only on the last of several attempts.
+    fn model_for<'a>(&'a self, model: &'a str, attempt: u32) -> &'a str {
+        let max_attempts = self.retry.attempts();
+        match &self.fallback_model {
+            Some(fallback) if attempt > 1 && attempt == max_attempts => fallback,
+            _ => model,
+        }
+    }
+}
+
+impl Default for ProviderConfig {
+    /// OpenAI without a key, for when the key has already been configured
+    /// through `openai::set_key`.
+    fn default() -> Self {
+        Self::openai(String::new())
+    }
+}
+
+// Parse an optional numeric environment variable.
+fn parse_var<T: std::str::FromStr>(name: &str) -> Result<Option<T>, String> {
+    match env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("{} must be a number, got '{}'", name, value)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Why a completion could not be produced.
+#[derive(Debug)]
+pub enum AiError {
+    /// The provider did not answer within the configured timeout.
+    Timeout,
+    /// The provider returned an error, or could not be reached.
+    Api {
+        message: String,
+        /// Whether the same request might succeed if tried again, as with
+        /// rate limits, server errors and connection failures.
+        retryable: bool,
+    },
+    /// The provider answered, but without any text.
+    EmptyResponse,
+}
+
+impl AiError {
+    /// Whether the request is worth retrying.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            AiError::Timeout => true,
+            AiError::Api { retryable, .. } => *retryable,
+            AiError::EmptyResponse => false,
+        }
+    }
+}
+
+impl fmt::Display for AiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AiError::Timeout => write!(f, "request timed out"),
+            AiError::Api { message, .. } => write!(f, "AI service error: {}", message),
+            AiError::EmptyResponse => write!(f, "AI service returned an empty response"),
+        }
+    }
+}
+
+impl std::error::Error for AiError {}
+
+/// A configured connection to an AI provider.
+pub struct AiProvider {
+    config: ProviderConfig,
+    http: reqwest::Client,
+}
+
+impl AiProvider {
+    pub fn new(config: ProviderConfig) -> Self {
+        // The openai crate keeps its key in a global.
+        if config.provider == Provider::OpenAi && !config.api_key.is_empty() {
+            set_key(config.api_key.clone());
+        }
+        AiProvider {
+            config,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn config(&self) -> &ProviderConfig {
+        &self.config
+    }
+
+    /// Answer `question` using `system` as the system prompt. The returned
+    /// text is trimmed and never empty.
+    ///
+    /// Transient failures are retried according to the configured
+    /// [`RetryPolicy`], switching to the fallback model (if any) for the last
+    /// attempt. Every attempt is logged with its model and latency.
+    pub async fn complete(&self, system: &str, question: &str) -> Result<String, AiError> {
+        self.complete_with(&self.config.model, system, question)
+            .await
+    }
+
+    /// Like [`AiProvider::complete`], but asks `model` instead of the
+    /// configured model. The fallback model still applies.
+    pub async fn complete_with(
+        &self,
+        model: &str,
+        system: &str,
+        question: &str,
+    ) -> Result<String, AiError> {
+        let max_attempts = self.config.retry.attempts();
+        let mut attempt = 1;
+
+        loop {
+            let model = self.config.model_for(model, attempt);
+
+            let started = Instant::now();
+            let result = self.attempt(model, system, question).await;
+            let elapsed = started.elapsed().as_millis();
+
+            match result {
+                Ok(text) => {
+                    println!(
+                        "AI attempt {}/{} with {} succeeded in {}ms",
+                        attempt, max_attempts, model, elapsed
+                    );
+                    return Ok(text);
+                }
+                Err(why) if why.is_retryable() && attempt < max_attempts => {
+                    let delay = self.config.retry.delay(attempt);
+                    println!(
+                        "AI attempt {}/{} with {} failed in {}ms, retrying in {}ms: {}",
+                        attempt,
+                        max_attempts,
+                        model,
+                        elapsed,
+                        delay.as_millis(),
+                        why
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(why) => {
+                    println!(
+                        "AI attempt {}/{} with {} failed in {}ms: {}",
+                        attempt, max_attempts, model, elapsed, why
+                    );
+                    return Err(why);
+                }
+            }
+        }
+    }
+
+    // A single request to the provider, bounded by the configured timeout.
+    async fn attempt(&self, model: &str, system: &str, question: &str) -> Result<String, AiError> {
+        let request = async {
+            match self.config.provider {
+                Provider::OpenAi => self.complete_openai(model, system, question).await,
+                Provider::Anthropic => self.complete_anthropic(model, system, question).await,
+            }
+        };
+
+        let text = tokio::time::timeout(self.config.timeout, request)
+            .await
+            .map_err(|_| AiError::Timeout)??;
+
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(AiError::EmptyResponse);
+        }
+        Ok(text.to_string())
+    }
+
+    async fn complete_openai(
+        &self,
+        model: &str,
+        system: &str,
+        question: &str,
+    ) -> Result<String, AiError> {
+        let messages = vec![
+            ChatCompletionMessage {
+                role: ChatCompletionMessageRole::System,
+                content: Some(system.to_string()),
+                name: None,
+                function_call: None,
+            },
+            ChatCompletionMessage {
+                role: ChatCompletionMessageRole::User,
+                content: Some(question.to_string()),
+                name: None,
+                function_call: None,
+            },
+        ];
+
+        let chat_completion = ChatCompletion::builder(model, messages)
+            .max_tokens(self.config.max_tokens)
+            .create()
+            .await
+            .map_err(|why| AiError::Api {
+                // OpenAI reports bad keys, unknown models and malformed
+                // requests as invalid_request_error, and an exhausted
+                // balance as insufficient_quota; retrying won't fix those.
+                // Rate limits, server errors and transport failures can.
+                retryable: !matches!(
+                    why.error_type.as_str(),
+                    "invalid_request_error" | "insufficient_quota"
+                ),
+                message: why.message,
+            })?;
+
+        chat_completion
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.message.content)
+            .ok_or(AiError::EmptyResponse)
+    }
+
+    async fn complete_anthropic(
+        &self,
+        model: &str,
+        system: &str,
+        question: &str,
+    ) -> Result<String, AiError> {
+        let body = AnthropicRequest {
+            model,
+            max_tokens: self.config.max_tokens,
+            system,
+            messages: vec![AnthropicMessage {
+                role: "user",
+                content: question,
+            }],
+        };
+
+        let response = self
+            .http
+            .post(ANTHROPIC_MESSAGES_URL)
+            .header("x-api-key", &self.config.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(&body)
+            .send()
+            .await
+            .map_err(|why| AiError::Api {
+                message: why.to_string(),
+                retryable: true,
+            })?;
+
+        let status = response.status();
+        if !status.is_success() {
+            // 429 is a rate limit and 529 means Anthropic is overloaded; both
+            // are worth waiting out, as are other server errors.
+            let retryable = status.as_u16() == 429 || status.is_server_error();
+            // Anthropic describes failures as {"error": {"message": ...}}, but
+            // fall back to the status line if the body is something else.
+            let message = match response.json::<AnthropicErrorResponse>().await {
+                Ok(error) => error.error.message,
+                Err(_) => status.to_string(),
+            };
+            return Err(AiError::Api { message, retryable });
+        }
+
+        let response: AnthropicResponse = response.json().await.map_err(|why| AiError::Api {
+            message: why.to_string(),
+            retryable: false,
+        })?;
+
+        Ok(response
+            .content
+            .into_iter()
+            .filter(|block| block.kind == "text")
+            .filter_map(|block| block.text)
+            .collect::<Vec<_>>()
+            .join(""))
+    }
+}
+
+#[derive(Serialize)]
+struct AnthropicRequest<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    system: &'a str,
+    messages: Vec<AnthropicMessage<'a>>,
+}
+
+#[derive(Serialize)]
+struct AnthropicMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Deserialize)]
+struct AnthropicResponse {
+    content: Vec<AnthropicContent>,
+}
+
+#[derive(Deserialize)]
+struct AnthropicContent {
+    #[serde(rename = "type")]
+    kind: String,
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AnthropicErrorResponse {
+    error: AnthropicErrorBody,
+}
+
+#[derive(Deserialize)]
+struct AnthropicErrorBody {
+    message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_is_jittered_exponential_backoff() {
+        let retry = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(8),
+        };
+        for attempt in 1..=10 {
+            let backoff =
+                (Duration::from_millis(500) * 2u32.pow(attempt - 1)).min(Duration::from_secs(8));
+            let delay = retry.delay(attempt);
+            assert!(
+                delay >= backoff / 2 && delay <= backoff,
+                "attempt {}: {:?} not within {:?}",
+                attempt,
+                delay,
+                backoff
+            );
+        }
+    }
+
+    #[test]
+    fn delay_does_not_overflow() {
+        let retry = RetryPolicy::default();
+        assert!(retry.delay(u32::MAX) <= retry.max_delay);
+    }
+
+    #[test]
+    fn zero_attempts_means_one() {
+        let retry = RetryPolicy {
+            max_attempts: 0,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(retry.attempts(), 1);
+    }
+
+    #[test]
+    fn fallback_model_only_on_last_attempt() {
+        let mut config = ProviderConfig::openai("key");
+        config.fallback_model = Some("fallback".to_string());
+        config.retry.max_attempts = 3;
+
+        assert_eq!(config.model_for("primary", 1), "primary");
+        assert_eq!(config.model_for("primary", 2), "primary");
+        assert_eq!(config.model_for("primary", 3), "fallback");
+    }
+
+    #[test]
+    fn fallback_model_applies_to_any_requested_model() {
+        let mut config = ProviderConfig::openai("key");
+        config.fallback_model = Some("fallback".to_string());
+        config.retry.max_attempts = 2;
+
+        assert_eq!(config.model_for("gpt-4o", 1), "gpt-4o");
+        assert_eq!(config.model_for("gpt-4o", 2), "fallback");
+    }
+
+    #[test]
+    fn requested_model_is_used_without_fallback() {
+        let config = ProviderConfig::openai("key");
+        assert_eq!(config.model_for("gpt-4o", 3), "gpt-4o");
+    }
+
+    #[test]
+    fn fallback_model_not_used_without_retries() {
+        let mut config = ProviderConfig::openai("key");
+        config.fallback_model = Some("fallback".to_string());
+
+        config.retry.max_attempts = 1;
+        assert_eq!(config.model_for("primary", 1), "primary");
+        config.retry.max_attempts = 0;
+        assert_eq!(config.model_for("primary", 1), "primary");
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serenity::async_trait;
+use serenity::constants::MESSAGE_CODE_LIMIT;
+use serenity::model::channel::Message;
+use serenity::model::gateway::Ready;
+use serenity::model::id::{ChannelId, UserId};
+use serenity::prelude::*;
+
+use crate::ai_provider::{AiError, AiProvider};
+use crate::watchdog::{ResponseWatchdog, Verdict, WatchdogConfig};
+use crate::Command;
+
+// Map a failure to the message shown in the channel. Every command that talks
+// to the AI provider goes through here, so failures are reported in the voice
+// of the command's persona when it has its own error replies, and with a
+// generic message otherwise.
+fn error_reply<'a>(command: &'a Command, error: &AiError) -> &'a str {
+    match (command.error_replies(), error) {
+        (Some(replies), AiError::Timeout) => &replies.timeout,
+        (Some(replies), AiError::Api { .. }) => &replies.service,
+        (Some(replies), AiError::EmptyResponse) => &replies.empty,
+        (None, AiError::Timeout) => "Sorry, the request timed out. Please try again in a minute.",
+        (None, AiError::Api { .. }) => {
+            "Sorry, the AI service had a problem answering that. Please try again in a minute."
+        }
+        (None, AiError::EmptyResponse) => {
+            "The AI service came back with an empty answer. Try rephrasing your question."
+        }
+    }
+}
+
+// Split `text` into pieces short enough for Discord to accept, breaking at
+// the end of a line, or failing that a space, where possible.
+fn split_message(text: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = text.trim();
+    while rest.chars().count() > MESSAGE_CODE_LIMIT {
+        // Byte offset just past the last character that fits.
+        let limit = rest
+            .char_indices()
+            .nth(MESSAGE_CODE_LIMIT)
+            .map_or(rest.len(), |(index, _)| index);
+        let head = &rest[..limit];
+        let cut = head
+            .rfind('\n')
+            .or_else(|| head.rfind(' '))
+            .filter(|&index| index > 0)
+            .unwrap_or(limit);
+        let chunk = head[..cut].trim_end();
+        if !chunk.is_empty() {
+            chunks.push(chunk);
+        }
+        rest = rest[cut..].trim_start();
+    }
+    if !rest.is_empty() {
+        chunks.push(rest);
+    }
+    chunks
+}
+
+// How long a break is, for the watchdog notice: "30 second" or "5 minute".
+fn break_length(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{} second", secs)
+    } else {
+        format!("{} minute", secs.div_ceil(60))
+    }
+}
+
+// Messages are serialized per user and channel.
+type ConversationKey = (UserId, ChannelId);
+
+pub(crate) struct Handler {
+    ai: AiProvider,
+    // The AI commands this bot answers, in the order `/help` lists them.
+    commands: Vec<Command>,
+    // Bot accounts (relays, webhooks) whose messages are handled like a
+    // user's. Every other bot, including this one, is ignored.
+    trusted_bots: HashSet<UserId>,
+    // Caps how often the bot replies in each channel.
+    watchdog: Mutex<ResponseWatchdog>,
+    // One lock per (user, channel) conversation. Discord dispatches events
+    // concurrently, so without this two quick commands from the same user
+    // race each other to the AI provider at the same time.
+    conversations: Mutex<HashMap<ConversationKey, Arc<Mutex<()>>>>,
+}
+
+impl Handler {
+    pub(crate) fn new(
+        ai: AiProvider,
+        commands: Vec<Command>,
+        trusted_bots: HashSet<UserId>,
+        watchdog: WatchdogConfig,
+    ) -> Self {
+        Handler {
+            ai,
+            commands,
+            trusted_bots,
+            watchdog: Mutex::new(ResponseWatchdog::new(watchdog)),
+            conversations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Fetch (or create) the lock for a conversation.
+    async fn conversation(&self, key: ConversationKey) -> Arc<Mutex<()>> {
+        let mut conversations = self.conversations.lock().await;
+        conversations
+            .entry(key)
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone()
+    }
+
+    // Drop the lock for a conversation once nobody else is waiting on it, so
+    // the map doesn't grow with every user the bot has ever seen.
+    async fn release_conversation(&self, key: ConversationKey, conversation: Arc<Mutex<()>>) {
+        let mut conversations = self.conversations.lock().await;
+        // One reference is held by the map and one by us; anything more
+        // means another message is already queued behind this one.
+        if Arc::strong_count(&conversation) == 2 {
+            conversations.remove(&key);
+        }
+    }
+
+    // Whether `name` is something this bot answers: a built-in or an AI
+    // command.
+    fn is_command(&self, name: &str) -> bool {
+        name == "!ping"
+            || name == "/help"
+            || self.commands.iter().any(|command| command.name == name)
+    }
+
+    // Ask the AI provider to answer `question` using the command's system
+    // prompt and model. Always returns something to post, though it may be too long for
+    // a single message; failures are mapped through `error_reply` (the
+    // provider has already logged them).
+    async fn complete(&self, command: &Command, question: &str) -> String {
+        let model = command.model().unwrap_or(&self.ai.config().model);
+        match self
+            .ai
+            .complete_with(model, &command.prompt, question)
+            .await
+        {
+            Ok(answer) => answer,
+            Err(why) => error_reply(command, &why).to_string(),
+        }
+    }
+
+    async fn handle(&self, ctx: &Context, msgg: &Message) {
+        let msg = msgg.content.replace('\\', "");
+        let words: Vec<&str> = msg.split_whitespace().collect();
+
+        let Some(&name) = words.first() else {
+            return;
+        };
+        let command = self.commands.iter().find(|command| command.name == name);
+
+        // Check the reply rate before doing any work, so a loop doesn't keep
+        // spending AI quota while the bot is muted.
+        let (verdict, config) = {
+            let mut watchdog = self.watchdog.lock().await;
+            let verdict = watchdog.record(msgg.channel_id, Instant::now());
+            (verdict, watchdog.config().clone())
+        };
+        match verdict {
+            Verdict::Allow => {}
+            Verdict::Muted => {
+                println!(
+                    "Watchdog: ignoring {} in muted channel {}",
+                    name, msgg.channel_id
+                );
+                return;
+            }
+            Verdict::Tripped => {
+                println!(
+                    "Watchdog: more than {} replies in {}s in channel {}, muting for {}s",
+                    config.max_replies,
+                    config.window.as_secs(),
+                    msgg.channel_id,
+                    config.mute_for.as_secs()
+                );
+                let notice = format!(
+                    "I'm getting a lot of requests in here, so I'm taking a {} break.",
+                    break_length(config.mute_for)
+                );
+                if let Err(why) = msgg.channel_id.say(&ctx.http, notice).await {
+                    println!("Error sending message: {:?}", why);
+                }
+                if let Some(alert_channel) = config.alert_channel {
+                    let alert = format!(
+                        "Muted replies in {} for {}s after more than {} replies in {}s.",
+                        msgg.channel_id.mention(),
+                        config.mute_for.as_secs(),
+                        config.max_replies,
+                        config.window.as_secs()
+                    );
+                    if let Err(why) = alert_channel.say(&ctx.http, alert).await {
+                        println!("Error sending watchdog alert: {:?}", why);
+                    }
+                }
+                return;
+            }
+        }
+
+        let reply = match command {
+            // Without a question there's nothing to send; Anthropic rejects
+            // empty messages outright, and OpenAI would bill for a guess.
+            Some(_) if words.len() == 1 => format!("Usage: {} <question>", name),
+            Some(command) => {
+                println!("{}: '{}'", name, msg);
+                // Everything after the command name is the question.
+                self.complete(command, &words[1..].join(" ")).await
+            }
+            None if name == "!ping" => "Pong!".to_string(),
+            None => self.help_text(),
+        };
+
+        // Long answers are sent as several messages; serenity refuses to send
+        // anything over Discord's length limit.
+        //
+        // Sending a message can fail, due to a network error, an
+        // authentication error, or lack of permissions to post in the
+        // channel, so log to stdout when some error happens, with a
+        // description of it.
+        for chunk in split_message(&reply) {
+            if let Err(why) = msgg.channel_id.say(&ctx.http, chunk).await {
+                println!("Error sending message: {:?}", why);
+                break;
+            }
+        }
+    }
+
+    fn help_text(&self) -> String {
+        let mut help_text = "Available commands:\n- !ping\n".to_string();
+        for command in &self.commands {
+            help_text.push_str(&format!("- {}\n", command.name));
+        }
+        help_text.push_str("- /help\n");
+        help_text
+    }
+}
+
+#[async_trait]
+impl EventHandler for Handler {
+    // Set a handler for the `message` event - so that whenever a new message
+    // is received - the closure (or function) passed will be called.
+    //
+    // Event handlers are dispatched through a threadpool, and so multiple
+    // events can be dispatched simultaneously.
+    async fn message(&self, ctx: Context, msgg: Message) {
+        // Don't let other bots, or our own replies, trigger commands unless
+        // they have been explicitly trusted.
+        if msgg.author.bot && !self.trusted_bots.contains(&msgg.author.id) {
+            return;
+        }
+
+        // Most messages are ordinary chatter; ignore them before touching the
+        // conversation locks.
+        let is_command = match msgg.content.replace('\\', "").split_whitespace().next() {
+            Some(name) => self.is_command(name),
+            None => false,
+        };
+        if !is_command {
+            return;
+        }
+
+        // Handle commands from the same user in the same channel one at a
+        // time rather than concurrently. Events are dispatched on separate
+        // tasks, so this doesn't guarantee they run in the order they were
+        // sent, only that they don't overlap.
+        let key = (msgg.author.id, msgg.channel_id);
+        let conversation = self.conversation(key).await;
+        let turn = conversation.lock().await;
+
+        self.handle(&ctx, &msgg).await;
+
+        drop(turn);
+        self.release_conversation(key, conversation).await;
+    }
+
+    // Set a handler to be called on the `ready` event. This is called when a
+    // shard is booted, and a READY payload is sent by Discord. This payload
+    // contains data like the current user's guild Ids, current user data,
+    // private channels, and more.
+    //
+    // In this case, just print what the current user's username is.
+    async fn ready(&self, _: Context, ready: Ready) {
+        println!("{} is connected!", ready.user.name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai_provider::ProviderConfig;
+    use crate::ErrorReplies;
+
+    fn handler() -> Handler {
+        Handler::new(
+            AiProvider::new(ProviderConfig::default()),
+            Vec::new(),
+            HashSet::new(),
+            WatchdogConfig::default(),
+        )
+    }
+
+    fn api_error() -> AiError {
+        AiError::Api {
+            message: "overloaded".to_string(),
+            retryable: true,
+        }
+    }
+
+    #[test]
+    fn error_reply_uses_the_persona_lines() {
+        let command = Command::new("/pirate", "Arr.")
+            .with_error_replies(ErrorReplies::new("timeout", "service", "empty"));
+
+        assert_eq!(error_reply(&command, &AiError::Timeout), "timeout");
+        assert_eq!(error_reply(&command, &api_error()), "service");
+        assert_eq!(error_reply(&command, &AiError::EmptyResponse), "empty");
+    }
+
+    #[test]
+    fn error_reply_falls_back_to_generic_lines() {
+        let command = Command::new("/explain", "explain.");
+
+        assert!(error_reply(&command, &AiError::Timeout).contains("timed out"));
+        assert!(error_reply(&command, &api_error()).contains("had a problem"));
+        assert!(error_reply(&command, &AiError::EmptyResponse).contains("empty answer"));
+    }
+
+    #[test]
+    fn short_breaks_are_given_in_seconds() {
+        assert_eq!(break_length(Duration::from_secs(30)), "30 second");
+        assert_eq!(break_length(Duration::from_secs(60)), "1 minute");
+        assert_eq!(break_length(Duration::from_secs(90)), "2 minute");
+        assert_eq!(break_length(Duration::from_secs(300)), "5 minute");
+    }
+
+    #[test]
+    fn short_messages_are_sent_whole() {
+        assert_eq!(split_message("  Pong!\n"), vec!["Pong!"]);
+        assert!(split_message("   ").is_empty());
+    }
+
+    #[test]
+    fn long_messages_split_at_line_ends() {
+        let first = "a".repeat(1500);
+        let second = "b".repeat(1500);
+        let text = format!("{}\n{}", first, second);
+        assert_eq!(split_message(&text), vec![first.as_str(), second.as_str()]);
+    }
+
+    #[test]
+    fn long_messages_split_at_spaces_without_line_ends() {
+        let words = vec!["word"; 1000].join(" ");
+        let chunks = split_message(&words);
+        assert_eq!(chunks.len(), 3);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= MESSAGE_CODE_LIMIT);
+            assert!(chunk.starts_with("word") && chunk.ends_with("word"));
+        }
+        assert_eq!(chunks.join(" "), words);
+    }
+
+    #[test]
+    fn unbroken_text_is_split_by_characters() {
+        // Multi-byte characters count once each, as Discord counts them.
+        let text = "é".repeat(MESSAGE_CODE_LIMIT + 1);
+        let chunks = split_message(&text);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].chars().count(), MESSAGE_CODE_LIMIT);
+        assert_eq!(chunks[1], "é");
+    }
+
+    #[tokio::test]
+    async fn conversation_is_released_after_last_message() {
+        let handler = handler();
+        let key = (UserId::new(1), ChannelId::new(2));
+
+        let conversation = handler.conversation(key).await;
+        drop(conversation.lock().await);
+        handler.release_conversation(key, conversation).await;
+
+        assert!(handler.conversations.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn conversation_is_kept_while_a_message_is_queued() {
+        let handler = handler();
+        let key = (UserId::new(1), ChannelId::new(2));
+
+        let first = handler.conversation(key).await;
+        let turn = first.lock().await;
+        // A second message arrives while the first is being handled.
+        let second = handler.conversation(key).await;
+        assert!(Arc::ptr_eq(&first, &second));
+
+        drop(turn);
+        handler.release_conversation(key, first).await;
+        assert!(handler.conversations.lock().await.contains_key(&key));
+
+        drop(second.lock().await);
+        handler.release_conversation(key, second).await;
+        assert!(handler.conversations.lock().await.is_empty());
+    }
+}
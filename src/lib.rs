@@ -0,0 +1,408 @@
+//! Muppet chatbot for Discord fun, usable as a library.
+//!
+//! The `bot` binary is a thin wrapper around [`Bot`]. Other projects can
+//! embed the same bot and add their own personas as commands:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), serenity::Error> {
+//! persona::Bot::builder(std::env::var("DISCORD_MUPPET_FRIEND").unwrap())
+//!     .provider(persona::ai_provider::ProviderConfig::from_env().unwrap())
+//!     .command("/pirate", "You are a pirate. Answer everything like a pirate would.")
+//!     .run()
+//!     .await
+//! # }
+//! ```
+
+pub mod ai_provider;
+mod handler;
+pub mod self_test;
+pub mod watchdog;
+
+use std::collections::HashSet;
+
+use serenity::model::id::UserId;
+use serenity::prelude::*;
+
+use ai_provider::{AiProvider, ProviderConfig};
+use handler::Handler;
+use self_test::SelfTestReport;
+use watchdog::WatchdogConfig;
+
+// A command every bot starts with.
+struct DefaultCommand {
+    name: &'static str,
+    prompt: &'static str,
+    // (timeout, service, empty) replies in the voice of the prompt, if the
+    // generic ones would sound out of place.
+    error_replies: Option<(&'static str, &'static str, &'static str)>,
+}
+
+const DEFAULT_COMMANDS: &[DefaultCommand] = &[
+    DefaultCommand {
+        name: "/hey",
+        prompt: "You are a muppet expert.  All you want to talk about is muppets.  Your favorite muppet is kermit the frog, but you like mrs. piggy too.",
+        error_replies: Some((
+            "I was digging through my muppet trivia and lost track of time! Ask me again in a minute.",
+            "My muppet archives are closed for a moment. Try me again soon and we'll get back to Kermit and Miss Piggy!",
+            "I'm speechless, and that never happens when the topic is muppets. Ask me again?",
+        )),
+    },
+    DefaultCommand {
+        name: "/explain",
+        prompt: "explain.",
+        error_replies: None,
+    },
+    DefaultCommand {
+        name: "/simple",
+        prompt: "explain in a simple and consise way. give analogies a beginner might understand.",
+        error_replies: None,
+    },
+    DefaultCommand {
+        name: "/steps",
+        prompt: "break this out into steps.",
+        error_replies: None,
+    },
+    DefaultCommand {
+        name: "/recipe",
+        prompt: "Respond with a recipie if this prompt has food. If it does not have food, return 'gimmie some food to work with'.",
+        error_replies: Some((
+            "Your recipe is still in the oven. Try again in a minute.",
+            "The kitchen is closed for a moment. Try again soon and gimmie that food again.",
+            "That recipe came out empty. Gimmie some food to work with and try again.",
+        )),
+    },
+];
+
+/// What an AI command says when it can't produce an answer, so a persona can
+/// fail in its own voice. Commands without these use generic messages.
+#[derive(Clone, Debug)]
+pub struct ErrorReplies {
+    /// The AI provider didn't answer in time.
+    pub timeout: String,
+    /// The AI provider returned an error or couldn't be reached.
+    pub service: String,
+    /// The AI provider answered, but with nothing to post.
+    pub empty: String,
+}
+
+impl ErrorReplies {
+    pub fn new(
+        timeout: impl Into<String>,
+        service: impl Into<String>,
+        empty: impl Into<String>,
+    ) -> Self {
+        ErrorReplies {
+            timeout: timeout.into(),
+            service: service.into(),
+            empty: empty.into(),
+        }
+    }
+}
+
+/// An AI command: messages starting with `name` are answered by the AI
+/// provider using `prompt` as the system prompt.
+#[derive(Clone, Debug)]
+pub struct Command {
+    name: String,
+    prompt: String,
+    model: Option<String>,
+    error_replies: Option<ErrorReplies>,
+}
+
+impl Command {
+    pub fn new(name: impl Into<String>, prompt: impl Into<String>) -> Self {
+        Command {
+            name: name.into(),
+            prompt: prompt.into(),
+            model: None,
+            error_replies: None,
+        }
+    }
+
+    /// Answer with `model` instead of the provider's configured model, e.g.
+    /// a larger model for a command that needs longer explanations. It must
+    /// be a model of the configured provider.
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    /// Reply with `replies` instead of the generic messages when the AI
+    /// provider fails.
+    pub fn with_error_replies(mut self, replies: ErrorReplies) -> Self {
+        self.error_replies = Some(replies);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn prompt(&self) -> &str {
+        &self.prompt
+    }
+
+    pub fn model(&self) -> Option<&str> {
+        self.model.as_deref()
+    }
+
+    pub fn error_replies(&self) -> Option<&ErrorReplies> {
+        self.error_replies.as_ref()
+    }
+}
+
+/// A configured bot, ready to connect to Discord. Create one with
+/// [`Bot::builder`].
+pub struct Bot {
+    token: String,
+    provider: ProviderConfig,
+    commands: Vec<Command>,
+    trusted_bots: HashSet<UserId>,
+    watchdog: WatchdogConfig,
+}
+
+impl Bot {
+    /// Start configuring a bot that logs in with the given Discord bot token.
+    pub fn builder(token: impl Into<String>) -> BotBuilder {
+        BotBuilder {
+            bot: Bot {
+                token: token.into(),
+                provider: ProviderConfig::default(),
+                commands: DEFAULT_COMMANDS
+                    .iter()
+                    .map(|default| {
+                        let command = Command::new(default.name, default.prompt);
+                        match default.error_replies {
+                            Some((timeout, service, empty)) => command
+                                .with_error_replies(ErrorReplies::new(timeout, service, empty)),
+                            None => command,
+                        }
+                    })
+                    .collect(),
+                trusted_bots: HashSet::new(),
+                watchdog: WatchdogConfig::default(),
+            },
+        }
+    }
+
+    /// The AI commands this bot will answer.
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    /// Check the Discord token and the AI provider without connecting to the
+    /// gateway. See [`self_test`] for what is covered.
+    pub async fn self_test(&self) -> SelfTestReport {
+        self_test::run(&self.token, &self.provider).await
+    }
+
+    /// Connect to Discord and handle messages until the client stops.
+    pub async fn run(self) -> Result<(), SerenityError> {
+        // Set gateway intents, which decides what events the bot will be notified about
+        let intents = GatewayIntents::GUILD_MESSAGES
+            | GatewayIntents::DIRECT_MESSAGES
+            | GatewayIntents::MESSAGE_CONTENT;
+
+        // Create a new instance of the Client, logging in as a bot. This will
+        // automatically prepend your bot token with "Bot ", which is a requirement
+        // by Discord for bot users.
+        let mut client = Client::builder(&self.token, intents)
+            .event_handler(Handler::new(
+                AiProvider::new(self.provider),
+                self.commands,
+                self.trusted_bots,
+                self.watchdog,
+            ))
+            .await?;
+
+        // Finally, start a single shard, and start listening to events.
+        //
+        // Shards will automatically attempt to reconnect, and will perform
+        // exponential backoff until it reconnects.
+        client.start().await
+    }
+}
+
+/// Builder for [`Bot`].
+pub struct BotBuilder {
+    bot: Bot,
+}
+
+impl BotBuilder {
+    /// The AI provider, model and limits used for every AI command. Defaults
+    /// to OpenAI with a key already configured through `openai::set_key`.
+    pub fn provider(mut self, config: ProviderConfig) -> Self {
+        self.bot.provider = config;
+        self
+    }
+
+    /// Shorthand for using OpenAI with the default model and limits.
+    pub fn openai_key(self, key: impl Into<String>) -> Self {
+        self.provider(ProviderConfig::openai(key))
+    }
+
+    /// Add an AI command, replacing any existing command with the same name.
+    /// `!ping` and `/help` are built in and cannot be replaced.
+    pub fn command(self, name: impl Into<String>, prompt: impl Into<String>) -> Self {
+        self.add_command(Command::new(name, prompt))
+    }
+
+    /// Like [`BotBuilder::command`], for a command built with its own
+    /// [`ErrorReplies`].
+    pub fn add_command(mut self, command: Command) -> Self {
+        match self
+            .bot
+            .commands
+            .iter_mut()
+            .find(|existing| existing.name == command.name)
+        {
+            Some(existing) => *existing = command,
+            None => self.bot.commands.push(command),
+        }
+        self
+    }
+
+    /// Set what an existing AI command says when the AI provider fails. Has
+    /// no effect if there is no command called `name`.
+    pub fn error_replies(mut self, name: &str, replies: ErrorReplies) -> Self {
+        if let Some(command) = self
+            .bot
+            .commands
+            .iter_mut()
+            .find(|command| command.name == name)
+        {
+            command.error_replies = Some(replies);
+        }
+        self
+    }
+
+    /// Set the model an existing AI command is answered with; see
+    /// [`Command::with_model`]. Has no effect if there is no command called
+    /// `name`.
+    pub fn model_for(mut self, name: &str, model: impl Into<String>) -> Self {
+        if let Some(command) = self
+            .bot
+            .commands
+            .iter_mut()
+            .find(|command| command.name == name)
+        {
+            command.model = Some(model.into());
+        }
+        self
+    }
+
+    /// Remove an AI command, such as one of the defaults.
+    pub fn without_command(mut self, name: &str) -> Self {
+        self.bot.commands.retain(|command| command.name != name);
+        self
+    }
+
+    /// Let another bot (or webhook) trigger commands. Messages from any other
+    /// bot account are ignored.
+    pub fn trusted_bot(mut self, id: UserId) -> Self {
+        self.bot.trusted_bots.insert(id);
+        self
+    }
+
+    /// Limit how often the bot replies in a single channel. By default it
+    /// goes quiet for five minutes after ten replies within a minute.
+    pub fn watchdog(mut self, config: WatchdogConfig) -> Self {
+        self.bot.watchdog = config;
+        self
+    }
+
+    /// Finish configuring the bot.
+    pub fn build(self) -> Bot {
+        self.bot
+    }
+
+    /// Shorthand for `.build().run()`.
+    pub async fn run(self) -> Result<(), SerenityError> {
+        self.build().run().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replies() -> ErrorReplies {
+        ErrorReplies::new("timeout", "service", "empty")
+    }
+
+    fn find<'a>(bot: &'a Bot, name: &str) -> Option<&'a Command> {
+        bot.commands().iter().find(|command| command.name() == name)
+    }
+
+    #[test]
+    fn defaults_keep_their_error_replies() {
+        let bot = Bot::builder("token").build();
+        assert!(find(&bot, "/hey").unwrap().error_replies().is_some());
+        assert!(find(&bot, "/explain").unwrap().error_replies().is_none());
+    }
+
+    #[test]
+    fn replacing_a_default_drops_its_error_replies() {
+        let bot = Bot::builder("token")
+            .command("/hey", "You are a pirate.")
+            .build();
+
+        let hey = find(&bot, "/hey").unwrap();
+        assert_eq!(hey.prompt(), "You are a pirate.");
+        assert!(hey.error_replies().is_none());
+        assert_eq!(bot.commands().len(), DEFAULT_COMMANDS.len());
+    }
+
+    #[test]
+    fn add_command_keeps_its_own_error_replies() {
+        let bot = Bot::builder("token")
+            .add_command(Command::new("/pirate", "Arr.").with_error_replies(replies()))
+            .build();
+
+        let pirate = bot.commands().last().unwrap();
+        assert_eq!(pirate.name(), "/pirate");
+        assert_eq!(pirate.error_replies().unwrap().timeout, "timeout");
+    }
+
+    #[test]
+    fn error_replies_sets_existing_command() {
+        let bot = Bot::builder("token")
+            .error_replies("/explain", replies())
+            .build();
+        assert_eq!(
+            find(&bot, "/explain")
+                .unwrap()
+                .error_replies()
+                .unwrap()
+                .service,
+            "service"
+        );
+    }
+
+    #[test]
+    fn error_replies_for_unknown_command_does_nothing() {
+        let bot = Bot::builder("token")
+            .error_replies("/nope", replies())
+            .build();
+        assert!(find(&bot, "/nope").is_none());
+        assert_eq!(bot.commands().len(), DEFAULT_COMMANDS.len());
+    }
+
+    #[test]
+    fn model_for_sets_existing_command_only() {
+        let bot = Bot::builder("token")
+            .model_for("/explain", "gpt-4o")
+            .model_for("/nope", "gpt-4o")
+            .build();
+        assert_eq!(find(&bot, "/explain").unwrap().model(), Some("gpt-4o"));
+        assert_eq!(find(&bot, "/simple").unwrap().model(), None);
+        assert!(find(&bot, "/nope").is_none());
+    }
+
+    #[test]
+    fn without_command_removes_it() {
+        let bot = Bot::builder("token").without_command("/recipe").build();
+        assert!(find(&bot, "/recipe").is_none());
+        assert_eq!(bot.commands().len(), DEFAULT_COMMANDS.len() - 1);
+    }
+}
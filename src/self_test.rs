@@ -0,0 +1,121 @@
+//! Optional startup checks.
+//!
+//! Misconfiguration otherwise only shows up when the first command fails:
+//! a bad Discord token, a missing message content intent, or an AI provider
+//! that rejects the key or model. [`run`] checks all of them up front and
+//! collects the results into one report.
+
+use std::fmt;
+
+use serenity::http::Http;
+use serenity::model::application::ApplicationFlags;
+
+use crate::ai_provider::{AiProvider, ProviderConfig};
+
+/// The outcome of a single startup check.
+pub struct Check {
+    pub name: &'static str,
+    /// A short description of what was found, or why the check failed.
+    pub result: Result<String, String>,
+}
+
+/// The outcome of every startup check.
+pub struct SelfTestReport {
+    pub checks: Vec<Check>,
+}
+
+impl SelfTestReport {
+    /// Whether every check succeeded.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.result.is_ok())
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Startup self-test:")?;
+        for check in &self.checks {
+            match &check.result {
+                Ok(detail) => writeln!(f, "  [ok]   {}: {}", check.name, detail)?,
+                Err(why) => writeln!(f, "  [FAIL] {}: {}", check.name, why)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check the Discord token, the message content intent and the AI provider.
+pub async fn run(token: &str, provider: &ProviderConfig) -> SelfTestReport {
+    let http = Http::new(token);
+
+    // A single attempt is enough to tell whether the provider works. Retrying
+    // would hold up startup for minutes when it's down, and the fallback model
+    // could hide a misconfigured primary one.
+    let mut provider = provider.clone();
+    provider.retry.max_attempts = 1;
+    let ai = AiProvider::new(provider);
+
+    SelfTestReport {
+        checks: vec![
+            check_token(&http).await,
+            check_message_content_intent(&http).await,
+            check_ai_provider(&ai).await,
+        ],
+    }
+}
+
+async fn check_token(http: &Http) -> Check {
+    let result = match http.get_current_user().await {
+        Ok(user) if user.bot => Ok(format!("logged in as {}", user.name)),
+        Ok(user) => Err(format!("{} is not a bot account", user.name)),
+        Err(why) => Err(format!("token rejected by Discord: {}", why)),
+    };
+
+    Check {
+        name: "Discord token",
+        result,
+    }
+}
+
+// Commands are read from message text, which Discord only delivers when the
+// application has the (privileged) message content intent enabled.
+async fn check_message_content_intent(http: &Http) -> Check {
+    let result = match http.get_current_application_info().await {
+        Ok(info) => {
+            let flags = info.flags.unwrap_or_else(ApplicationFlags::empty);
+            if flags.intersects(
+                ApplicationFlags::GATEWAY_MESSAGE_CONTENT
+                    | ApplicationFlags::GATEWAY_MESSAGE_CONTENT_LIMITED,
+            ) {
+                Ok("enabled".to_string())
+            } else {
+                Err(format!(
+                    "not enabled for {}; turn it on in the developer portal",
+                    info.name
+                ))
+            }
+        }
+        Err(why) => Err(format!("couldn't fetch application info: {}", why)),
+    };
+
+    Check {
+        name: "Message content intent",
+        result,
+    }
+}
+
+async fn check_ai_provider(ai: &AiProvider) -> Check {
+    let config = ai.config();
+    let result = match ai.complete("Reply with the single word: ok", "ok?").await {
+        Ok(_) => Ok(format!(
+            "{:?} answered with {}",
+            config.provider, config.model
+        )),
+        Err(why) => Err(format!("{:?} ({}): {}", config.provider, config.model, why)),
+    };
+
+    Check {
+        name: "AI provider",
+        result,
+    }
+}
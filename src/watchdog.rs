@@ -0,0 +1,226 @@
+//! Guard against the bot being baited into reply loops.
+//!
+//! Two bots answering each other, or a user spamming commands, can keep the
+//! bot replying (and spending AI quota) indefinitely. The watchdog caps how
+//! many replies the bot sends per channel within a time window; when a
+//! channel goes over the cap the bot stops replying there for a while, and
+//! says so in the alert channel if one is configured.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use serenity::model::id::ChannelId;
+
+/// Limits enforced by the watchdog.
+#[derive(Clone, Debug)]
+pub struct WatchdogConfig {
+    /// Most replies the bot will send in one channel within `window`. Zero
+    /// turns the watchdog off.
+    pub max_replies: usize,
+    pub window: Duration,
+    /// How long the bot stays quiet in a channel after going over the cap.
+    pub mute_for: Duration,
+    /// Where to tell admins that a channel has been muted.
+    pub alert_channel: Option<ChannelId>,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            max_replies: 10,
+            window: Duration::from_secs(60),
+            mute_for: Duration::from_secs(5 * 60),
+            alert_channel: None,
+        }
+    }
+}
+
+/// What the watchdog decided about a reply.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    /// Go ahead and reply.
+    Allow,
+    /// This reply would go over the cap; the channel has just been muted.
+    Tripped,
+    /// The channel is still muted.
+    Muted,
+}
+
+#[derive(Default)]
+struct ChannelActivity {
+    // When each reply within the current window was sent, oldest first.
+    recent: VecDeque<Instant>,
+    muted_until: Option<Instant>,
+}
+
+pub(crate) struct ResponseWatchdog {
+    config: WatchdogConfig,
+    channels: HashMap<ChannelId, ChannelActivity>,
+}
+
+impl ResponseWatchdog {
+    pub(crate) fn new(config: WatchdogConfig) -> Self {
+        ResponseWatchdog {
+            config,
+            channels: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn config(&self) -> &WatchdogConfig {
+        &self.config
+    }
+
+    /// Record that the bot wants to reply in `channel` at `now`.
+    pub(crate) fn record(&mut self, channel: ChannelId, now: Instant) -> Verdict {
+        if self.config.max_replies == 0 {
+            return Verdict::Allow;
+        }
+        let window = self.config.window;
+
+        // Forget channels that have gone quiet so the map stays small.
+        self.channels.retain(|_, activity| {
+            activity.muted_until.is_some_and(|until| now < until)
+                || activity
+                    .recent
+                    .back()
+                    .is_some_and(|last| now.duration_since(*last) < window)
+        });
+
+        let activity = self.channels.entry(channel).or_default();
+
+        if let Some(until) = activity.muted_until {
+            if now < until {
+                return Verdict::Muted;
+            }
+            activity.muted_until = None;
+            activity.recent.clear();
+        }
+
+        while let Some(oldest) = activity.recent.front() {
+            if now.duration_since(*oldest) < window {
+                break;
+            }
+            activity.recent.pop_front();
+        }
+
+        if activity.recent.len() >= self.config.max_replies {
+            activity.muted_until = Some(now + self.config.mute_for);
+            return Verdict::Tripped;
+        }
+
+        activity.recent.push_back(now);
+        Verdict::Allow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watchdog() -> ResponseWatchdog {
+        ResponseWatchdog::new(WatchdogConfig {
+            max_replies: 3,
+            window: Duration::from_secs(60),
+            mute_for: Duration::from_secs(300),
+            alert_channel: None,
+        })
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn trips_after_max_replies_and_stays_muted() {
+        let mut watchdog = watchdog();
+        let channel = ChannelId::new(1);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(watchdog.record(channel, start), Verdict::Allow);
+        }
+        assert_eq!(watchdog.record(channel, start), Verdict::Tripped);
+        assert_eq!(watchdog.record(channel, start + secs(10)), Verdict::Muted);
+        assert_eq!(watchdog.record(channel, start + secs(299)), Verdict::Muted);
+    }
+
+    #[test]
+    fn other_channels_are_not_muted() {
+        let mut watchdog = watchdog();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            watchdog.record(ChannelId::new(1), start);
+        }
+        assert_eq!(watchdog.record(ChannelId::new(1), start), Verdict::Tripped);
+        assert_eq!(watchdog.record(ChannelId::new(2), start), Verdict::Allow);
+    }
+
+    #[test]
+    fn channel_resets_after_mute_expires() {
+        let mut watchdog = watchdog();
+        let channel = ChannelId::new(1);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            watchdog.record(channel, start);
+        }
+        assert_eq!(watchdog.record(channel, start), Verdict::Tripped);
+
+        // The replies from before the mute don't count against the channel.
+        let later = start + secs(300);
+        for _ in 0..3 {
+            assert_eq!(watchdog.record(channel, later), Verdict::Allow);
+        }
+        assert_eq!(watchdog.record(channel, later), Verdict::Tripped);
+    }
+
+    #[test]
+    fn old_replies_slide_out_of_window() {
+        let mut watchdog = watchdog();
+        let channel = ChannelId::new(1);
+        let start = Instant::now();
+
+        assert_eq!(watchdog.record(channel, start), Verdict::Allow);
+        assert_eq!(watchdog.record(channel, start + secs(1)), Verdict::Allow);
+        assert_eq!(watchdog.record(channel, start + secs(2)), Verdict::Allow);
+
+        // The first reply has left the window, making room for one more.
+        assert_eq!(watchdog.record(channel, start + secs(60)), Verdict::Allow);
+        assert_eq!(watchdog.record(channel, start + secs(60)), Verdict::Tripped);
+    }
+
+    #[test]
+    fn forgets_quiet_channels() {
+        let mut watchdog = watchdog();
+        let start = Instant::now();
+
+        watchdog.record(ChannelId::new(1), start);
+        for _ in 0..4 {
+            watchdog.record(ChannelId::new(2), start);
+        }
+        assert_eq!(watchdog.channels.len(), 2);
+
+        // Channel 1 has been quiet for a whole window; channel 2 is still muted.
+        watchdog.record(ChannelId::new(3), start + secs(60));
+        assert!(!watchdog.channels.contains_key(&ChannelId::new(1)));
+        assert!(watchdog.channels.contains_key(&ChannelId::new(2)));
+
+        // Once its mute is over, channel 2 is forgotten too.
+        watchdog.record(ChannelId::new(3), start + secs(300));
+        assert!(!watchdog.channels.contains_key(&ChannelId::new(2)));
+    }
+
+    #[test]
+    fn zero_max_replies_disables_the_watchdog() {
+        let mut watchdog = ResponseWatchdog::new(WatchdogConfig {
+            max_replies: 0,
+            ..WatchdogConfig::default()
+        });
+        let start = Instant::now();
+
+        for _ in 0..100 {
+            assert_eq!(watchdog.record(ChannelId::new(1), start), Verdict::Allow);
+        }
+    }
+}